@@ -7,23 +7,75 @@ use crate::spawn::*;
 use crate::tasks::{Task, Tasks};
 use cocoa::appkit::{NSApp, NSApplication, NSApplicationActivationPolicyRegular};
 use cocoa::base::{id, nil};
+use core_foundation::base::CFRelease;
 use core_foundation::date::CFAbsoluteTimeGetCurrent;
 use core_foundation::runloop::*;
 use failure::Fallible;
 use objc::*;
 use promise::BasicExecutor;
+use std::any::Any;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
 use std::rc::Rc;
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::time::Duration;
 
 pub struct Connection {
     ns_app: id,
     pub(crate) windows: RefCell<HashMap<usize, Rc<RefCell<WindowInner>>>>,
     pub(crate) next_window_id: AtomicUsize,
+    next_timer_id: AtomicUsize,
+    timers: RefCell<HashMap<usize, TimerRef>>,
+    dirty_windows: RefCell<HashSet<usize>>,
+    painting: AtomicBool,
+    main_run_loop: RunLoopRef,
+    panic_payload: RefCell<Option<Box<dyn Any + Send>>>,
     tasks: Tasks,
 }
 
+/// Wrapper around the main thread's `CFRunLoopRef`.  CoreFoundation run loop
+/// references are safe to hand to and signal from other threads, so we mark
+/// the wrapper `Send`/`Sync` to allow caching the main run loop and waking it
+/// when work is posted from a background thread.
+struct RunLoopRef(CFRunLoopRef);
+unsafe impl Send for RunLoopRef {}
+unsafe impl Sync for RunLoopRef {}
+
+/// The wakeup source and main run loop, stashed as raw pointers so that the
+/// spawn queue can signal the main thread from any thread.  Populated once in
+/// `create_new`.
+static WAKEUP_SOURCE: AtomicUsize = AtomicUsize::new(0);
+static MAIN_RUN_LOOP: AtomicUsize = AtomicUsize::new(0);
+
+/// Run `f` as a CoreFoundation/Objective-C callout, catching any panic so
+/// that the unwind does not cross the C frame (which is undefined behavior).
+/// A caught panic is stashed on the connection and the message loop is
+/// stopped so that the panic can be resumed on the main thread.
+fn guard_callout<F: FnOnce()>(f: F) {
+    if let Err(payload) = catch_unwind(AssertUnwindSafe(f)) {
+        if let Some(conn) = Connection::get() {
+            conn.store_panic(payload);
+        }
+    }
+}
+
+/// Signal the spawn-queue run loop source and wake the main run loop so that
+/// newly enqueued work runs promptly even if the main loop is idle in
+/// `BeforeWaiting`.  A no-op before the source has been installed.
+fn wake_main_run_loop() {
+    use std::sync::atomic::Ordering;
+    let source = WAKEUP_SOURCE.load(Ordering::Relaxed);
+    let run_loop = MAIN_RUN_LOOP.load(Ordering::Relaxed);
+    if source == 0 || run_loop == 0 {
+        return;
+    }
+    unsafe {
+        CFRunLoopSourceSignal(source as CFRunLoopSourceRef);
+        CFRunLoopWakeUp(run_loop as CFRunLoopRef);
+    }
+}
+
 impl Connection {
     pub(crate) fn create_new() -> Fallible<Self> {
         // Ensure that the SPAWN_QUEUE is created; it will have nothing
@@ -33,12 +85,21 @@ impl Connection {
         unsafe {
             let ns_app = NSApp();
             ns_app.setActivationPolicy_(NSApplicationActivationPolicyRegular);
+            let main_run_loop = CFRunLoopGetMain();
             let conn = Self {
                 ns_app,
                 windows: RefCell::new(HashMap::new()),
                 tasks: Default::default(),
                 next_window_id: AtomicUsize::new(1),
+                next_timer_id: AtomicUsize::new(1),
+                timers: RefCell::new(HashMap::new()),
+                dirty_windows: RefCell::new(HashSet::new()),
+                painting: AtomicBool::new(false),
+                main_run_loop: RunLoopRef(main_run_loop),
+                panic_payload: RefCell::new(None),
             };
+            conn.add_paint_observer();
+            conn.add_spawn_source();
             Ok(conn)
         }
     }
@@ -67,6 +128,162 @@ impl Connection {
     pub fn executor() -> impl BasicExecutor {
         SpawnQueueExecutor {}
     }
+
+    /// Mark `window_id` as needing a repaint.  The paint itself is deferred
+    /// until the run loop is about to wait, so that a burst of invalidations
+    /// between two waits collapses into a single draw.
+    pub fn invalidate_window(&self, window_id: usize) {
+        use std::sync::atomic::Ordering;
+        self.dirty_windows.borrow_mut().insert(window_id);
+        // If we're invalidating from inside a paint, this turn's dirty set has
+        // already been drained; wake the loop so the newly dirtied window is
+        // serviced on a follow-up `BeforeWaiting` turn rather than stalling
+        // until some unrelated event arrives.
+        if self.painting.load(Ordering::Relaxed) {
+            unsafe {
+                CFRunLoopWakeUp(self.main_run_loop.0);
+            }
+        }
+    }
+
+    /// Record a panic payload caught at an FFI boundary and stop the message
+    /// loop.  The payload is resumed by `run_message_loop` once `NSApp::run`
+    /// has returned, surfacing it cleanly on the main thread.
+    fn store_panic(&self, payload: Box<dyn Any + Send>) {
+        let mut slot = self.panic_payload.borrow_mut();
+        if slot.is_none() {
+            *slot = Some(payload);
+        }
+        self.terminate_message_loop();
+    }
+
+    /// Run the main run loop for up to `timeout`, servicing timers, the spawn
+    /// queue and window events, then report why it returned.  Unlike
+    /// `run_message_loop` this does not hand the main thread to `NSApp::run`,
+    /// so it can drive a modal sub-loop or a deterministic integration test.
+    pub fn pump_events(&self, timeout: Duration) -> PumpResult {
+        let result =
+            unsafe { CFRunLoopRunInMode(kCFRunLoopDefaultMode, duration_secs(timeout), 0) };
+        // A callout may have panicked while we were pumping; since this caller
+        // drives the loop only through `pump_events`, surface it here rather
+        // than waiting for `run_message_loop` to resume it.
+        if let Some(payload) = self.panic_payload.borrow_mut().take() {
+            resume_unwind(payload);
+        }
+        match result {
+            1 => PumpResult::Finished,
+            2 => PumpResult::Stopped,
+            3 => PumpResult::TimedOut,
+            4 => PumpResult::HandledSource,
+            _ => PumpResult::Finished,
+        }
+    }
+
+    /// Register the `kCFRunLoopBeforeWaiting` observer that drains the dirty
+    /// set once per run loop iteration.
+    fn add_paint_observer(&self) {
+        extern "C" fn observer_callback(
+            _observer: *mut __CFRunLoopObserver,
+            _activity: u64,
+            _info: *mut c_void,
+        ) {
+            if let Some(conn) = Connection::get() {
+                conn.paint_dirty_windows();
+            }
+        }
+
+        unsafe {
+            let observer = CFRunLoopObserverCreate(
+                std::ptr::null(),
+                K_CF_RUN_LOOP_BEFORE_WAITING,
+                1,
+                0,
+                observer_callback,
+                std::ptr::null_mut(),
+            );
+            CFRunLoopAddObserver(CFRunLoopGetCurrent(), observer, kCFRunLoopCommonModes);
+            // The run loop retained the observer when we added it; release the
+            // local +1 from `CFRunLoopObserverCreate` so it isn't leaked.
+            CFRelease(observer as *const c_void);
+        }
+    }
+
+    /// Install a version-0 run loop source whose `perform` callout drains the
+    /// spawn queue.  Background threads signal this source (via
+    /// `wake_main_run_loop`) so that cross-thread task posting is serviced
+    /// promptly instead of waiting for the next unrelated event.
+    fn add_spawn_source(&self) {
+        use std::sync::atomic::Ordering;
+
+        extern "C" fn perform(_info: *const c_void) {
+            guard_callout(|| {
+                SPAWN_QUEUE.run();
+            });
+        }
+
+        unsafe {
+            let mut context = CFRunLoopSourceContext {
+                version: 0,
+                info: std::ptr::null_mut(),
+                retain: None,
+                release: None,
+                copyDescription: None,
+                equal: None,
+                hash: None,
+                schedule: None,
+                cancel: None,
+                perform,
+            };
+            let source = CFRunLoopSourceCreate(std::ptr::null(), 0, &mut context);
+            CFRunLoopAddSource(self.main_run_loop.0, source, kCFRunLoopCommonModes);
+            WAKEUP_SOURCE.store(source as usize, Ordering::Relaxed);
+            MAIN_RUN_LOOP.store(self.main_run_loop.0 as usize, Ordering::Relaxed);
+            // The run loop retained the source when we added it; release the
+            // local +1 from `CFRunLoopSourceCreate` so it isn't leaked.  The
+            // run loop keeps it alive for the connection's lifetime, so the
+            // cached pointer in `WAKEUP_SOURCE` stays valid to signal.
+            CFRelease(source as *const c_void);
+        }
+    }
+
+    /// Paint each window that was invalidated since the last wait, exactly
+    /// once.  Windows dirtied from inside a paint (e.g. an animation that
+    /// invalidates itself) are left in the set and serviced on the next run
+    /// loop turn rather than recursing here.
+    fn paint_dirty_windows(&self) {
+        use std::sync::atomic::Ordering;
+        if self.painting.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        // Clear the in-progress flag no matter how we leave this scope; a
+        // paint that unwinds must not wedge the flag `true` and silently
+        // disable all future repaints for the process.
+        struct ClearOnDrop<'a>(&'a AtomicBool);
+        impl<'a> Drop for ClearOnDrop<'a> {
+            fn drop(&mut self) {
+                self.0.store(false, Ordering::Relaxed);
+            }
+        }
+        let _clear = ClearOnDrop(&self.painting);
+
+        let dirty: Vec<usize> = self.dirty_windows.borrow_mut().drain().collect();
+        guard_callout(|| {
+            for window_id in dirty {
+                if let Some(handle) = self.window_by_id(window_id) {
+                    handle.borrow_mut().paint();
+                }
+            }
+        });
+
+        // A window that dirtied itself mid-paint (e.g. a self-animating view)
+        // re-populated the set after we drained it; force another turn so it
+        // repaints without waiting for an unrelated event.
+        if !self.dirty_windows.borrow().is_empty() {
+            unsafe {
+                CFRunLoopWakeUp(self.main_run_loop.0);
+            }
+        }
+    }
 }
 
 /* Begin: workaround UB in CFRunLoopTimerContext struct.
@@ -104,10 +321,113 @@ extern "C" {
         timer: *mut __CFRunLoopTimer,
         mode: *const __CFString,
     );
+    fn CFRunLoopTimerInvalidate(timer: *mut __CFRunLoopTimer);
+    fn CFRunLoopTimerSetNextFireDate(timer: *mut __CFRunLoopTimer, fireDate: f64);
+    fn CFRunLoopTimerGetInterval(timer: *mut __CFRunLoopTimer) -> f64;
+    fn CFRunLoopObserverCreate(
+        allocator: *const c_void,
+        activities: u64,
+        repeats: u8,
+        order: i64,
+        callout: extern "C" fn(*mut __CFRunLoopObserver, u64, *mut c_void),
+        context: *mut CFRunLoopObserverContext,
+    ) -> *mut __CFRunLoopObserver;
+    fn CFRunLoopAddObserver(
+        rl: *mut __CFRunLoop,
+        observer: *mut __CFRunLoopObserver,
+        mode: *const __CFString,
+    );
+    fn CFRunLoopRunInMode(mode: *const __CFString, seconds: f64, returnAfterSourceHandled: u8)
+        -> i32;
+}
+
+/// The reason `pump_events` returned, mapped from CoreFoundation's
+/// `CFRunLoopRunResult` constants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PumpResult {
+    /// The run loop mode has no remaining sources or timers.
+    Finished,
+    /// The run loop was stopped with `CFRunLoopStop` (e.g. `NSApp stop:`).
+    Stopped,
+    /// The specified timeout elapsed.
+    TimedOut,
+    /// A source was handled and `return_after_source_handled` was set.
+    HandledSource,
+}
+
+#[repr(transparent)]
+pub struct __CFRunLoopObserver(c_void);
+
+#[repr(C)]
+#[allow(non_snake_case)]
+pub struct CFRunLoopObserverContext {
+    pub version: i64,
+    pub info: *mut c_void,
+    pub retain: Option<extern "C" fn(*const c_void) -> *const c_void>,
+    pub release: Option<extern "C" fn(*const c_void)>,
+    pub copyDescription: Option<extern "C" fn(*const c_void) -> *const __CFString>,
 }
 
+/// `kCFRunLoopBeforeWaiting` from CoreFoundation's `CFRunLoopActivity`.
+const K_CF_RUN_LOOP_BEFORE_WAITING: u64 = 1 << 5;
+
 /* End: UB workaround */
 
+/// The connection's owning reference to a live `CFRunLoopTimer`.  Stored in
+/// `Connection::timers`; dropping it (via `cancel`, a one-shot timer's first
+/// fire, or run loop teardown) invalidates the timer and releases the +1 that
+/// `CFRunLoopTimerCreate` handed us.
+struct TimerRef(*mut __CFRunLoopTimer);
+
+impl Drop for TimerRef {
+    fn drop(&mut self) {
+        unsafe {
+            CFRunLoopTimerInvalidate(self.0);
+            CFRelease(self.0 as *const c_void);
+        }
+    }
+}
+
+/// A lightweight, id-based handle to a timer scheduled on the run loop.  The
+/// connection owns the underlying `CFRunLoopTimer`; the handle merely refers
+/// to it by id, so dropping the handle does not stop the timer.  Call
+/// `cancel()` for that (the connection also cancels any outstanding timers
+/// during run loop teardown).
+pub struct TimerHandle {
+    id: usize,
+}
+
+impl TimerHandle {
+    /// Invalidate the timer so that it will never fire again and drop the
+    /// connection's owning reference, which releases the boxed closure.  A
+    /// no-op if the timer has already fired (one-shot) or been cancelled.
+    pub fn cancel(&self) {
+        if let Some(conn) = Connection::get() {
+            conn.timers.borrow_mut().remove(&self.id);
+        }
+    }
+
+    /// Move the next fire date to `interval` from now.  For a repeating
+    /// timer this changes only the next fire; the configured interval is
+    /// retained for subsequent fires.  A no-op once the timer is gone.
+    pub fn reschedule(&self, interval: Duration) {
+        if let Some(conn) = Connection::get() {
+            if let Some(timer) = conn.timers.borrow().get(&self.id) {
+                unsafe {
+                    CFRunLoopTimerSetNextFireDate(
+                        timer.0,
+                        CFAbsoluteTimeGetCurrent() + duration_secs(interval),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn duration_secs(interval: Duration) -> f64 {
+    (interval.as_secs() as f64) + (f64::from(interval.subsec_nanos()) / 1_000_000_000_f64)
+}
+
 impl ConnectionOps for Connection {
     fn terminate_message_loop(&self) {
         unsafe {
@@ -119,7 +439,13 @@ impl ConnectionOps for Connection {
         unsafe {
             self.ns_app.run();
         }
+        self.cancel_all_timers();
         self.windows.borrow_mut().clear();
+        // If a callout panicked, the unwind was caught at the FFI boundary and
+        // the loop stopped; resume it now that we are back on a Rust frame.
+        if let Some(payload) = self.panic_payload.borrow_mut().take() {
+            resume_unwind(payload);
+        }
         Ok(())
     }
 
@@ -130,52 +456,122 @@ impl ConnectionOps for Connection {
 
     fn wake_task_by_id(slot: usize) {
         SpawnQueueExecutor {}.execute(Box::new(move || {
-            let conn = Connection::get().unwrap();
-            conn.tasks.poll_by_slot(slot);
+            guard_callout(|| {
+                let conn = Connection::get().unwrap();
+                conn.tasks.poll_by_slot(slot);
+            });
         }));
+        // The work above may have been enqueued from a background thread; make
+        // sure the main run loop wakes to service it.
+        wake_main_run_loop();
     }
 
     fn schedule_timer<F: FnMut() + 'static>(&self, interval: std::time::Duration, callback: F) {
-        let secs_f64 =
-            (interval.as_secs() as f64) + (f64::from(interval.subsec_nanos()) / 1_000_000_000_f64);
+        let secs = duration_secs(interval);
+        self.make_timer(secs, secs, callback);
+    }
+}
 
-        let callback = Box::into_raw(Box::new(callback));
+impl Connection {
+    /// Schedule `callback` to fire every `interval`, returning a handle that
+    /// can cancel or reschedule it.  This is the macOS-specific counterpart
+    /// to the cross-platform `ConnectionOps::schedule_timer`, which discards
+    /// the handle.
+    pub fn schedule_timer<F: FnMut() + 'static>(
+        &self,
+        interval: Duration,
+        callback: F,
+    ) -> TimerHandle {
+        let secs = duration_secs(interval);
+        self.make_timer(secs, secs, callback)
+    }
+
+    /// Schedule `callback` to fire once after `interval` has elapsed.  The
+    /// timer self-invalidates on the first fire, so the returned handle's
+    /// `reschedule()` is a no-op after it has run; `cancel()` stops it
+    /// before it fires.
+    pub fn schedule_timer_once<F: FnMut() + 'static>(
+        &self,
+        interval: Duration,
+        callback: F,
+    ) -> TimerHandle {
+        self.make_timer(duration_secs(interval), 0.0, callback)
+    }
+
+    fn make_timer<F: FnMut() + 'static>(
+        &self,
+        fire_after_secs: f64,
+        interval_secs: f64,
+        callback: F,
+    ) -> TimerHandle {
+        // The boxed payload carries the closure plus the id of its map entry,
+        // so that a one-shot timer can prune itself when it fires.
+        struct TimerState<F> {
+            callback: F,
+            id: usize,
+        }
+
+        let id = self
+            .next_timer_id
+            .fetch_add(1, ::std::sync::atomic::Ordering::Relaxed);
+        let state = Box::into_raw(Box::new(TimerState { callback, id }));
 
         extern "C" fn timer_callback<F: FnMut()>(
-            _timer_ref: *mut __CFRunLoopTimer,
-            callback_ptr: *mut std::ffi::c_void,
+            timer_ref: *mut __CFRunLoopTimer,
+            state_ptr: *mut std::ffi::c_void,
         ) {
-            unsafe {
-                let callback: *mut F = callback_ptr as _;
-                (*callback)();
+            let state: *mut TimerState<F> = state_ptr as _;
+            // Capture everything we need from `state` *before* running the
+            // callback: if the callback cancels its own timer it drops the
+            // `TimerState` box out from under us, so `state` must not be
+            // touched once the callback has returned.
+            let id = unsafe { (*state).id };
+            let one_shot = unsafe { CFRunLoopTimerGetInterval(timer_ref) } == 0.0;
+            guard_callout(|| unsafe { ((*state).callback)() });
+            // One-shot timers are created with a zero interval; drop the
+            // connection's owning reference so the timer is invalidated,
+            // released and pruned from the map.  Repeating timers carry a
+            // nonzero interval and are left in place.
+            if one_shot {
+                if let Some(conn) = Connection::get() {
+                    conn.timers.borrow_mut().remove(&id);
+                }
             }
         }
 
         extern "C" fn release_callback<F: FnMut()>(info: *const std::ffi::c_void) {
-            let callback: Box<F> = unsafe { Box::from_raw(info as *mut F) };
-            drop(callback);
+            let state: Box<TimerState<F>> = unsafe { Box::from_raw(info as *mut TimerState<F>) };
+            drop(state);
         }
 
-        let timer_ref = unsafe {
-            CFRunLoopTimerCreate(
+        let timer = unsafe {
+            let timer = CFRunLoopTimerCreate(
                 std::ptr::null(),
-                CFAbsoluteTimeGetCurrent() + secs_f64,
-                secs_f64,
+                CFAbsoluteTimeGetCurrent() + fire_after_secs,
+                interval_secs,
                 0,
                 0,
                 timer_callback::<F>,
                 &mut CFRunLoopTimerContext {
                     copyDescription: None,
-                    info: callback as _,
+                    info: state as _,
                     release: Some(release_callback::<F>),
                     retain: None,
                     version: 0,
                 },
-            )
+            );
+            CFRunLoopAddTimer(CFRunLoopGetCurrent(), timer, kCFRunLoopCommonModes);
+            timer
         };
 
-        unsafe {
-            CFRunLoopAddTimer(CFRunLoopGetCurrent(), timer_ref, kCFRunLoopCommonModes);
-        }
+        self.timers.borrow_mut().insert(id, TimerRef(timer));
+        TimerHandle { id }
+    }
+
+    /// Invalidate and forget every outstanding timer.  Called during run
+    /// loop teardown so that boxed closures are dropped rather than leaked;
+    /// each dropped `TimerRef` invalidates and releases its timer.
+    fn cancel_all_timers(&self) {
+        self.timers.borrow_mut().clear();
     }
 }